@@ -7,15 +7,31 @@ use maplit::btreemap;
 use rusty_v8 as v8;
 use rusty_workers::types::*;
 use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::ffi::c_void;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
 const SAFE_AREA_SIZE: usize = 1048576;
 static LIBRT: &'static str = include_str!("../../librt/dist/main.js");
 
+/// Specifier used for the top-level user script when it is loaded as an ES module.
+const ENTRY_MODULE_SPECIFIER: &str = "main.js";
+
+/// Maximum number of op completions delivered through the shared queue in a single
+/// `_dispatchAsyncBatch` call. Chosen to comfortably cover a typical burst of concurrent
+/// `_callService` calls; anything beyond this falls back to the one-at-a-time path.
+const SHARED_QUEUE_MAX_RECORDS: usize = 256;
+
+/// Byte size of the arena backing the shared queue, handed to JS once at init. Each record is
+/// raw JSON, with boundaries tracked entirely by the header's offset table (see `SharedQueue`) --
+/// so this bounds the total size of a single batch's payloads rather than the record count (see
+/// `SHARED_QUEUE_MAX_RECORDS` for that).
+const SHARED_QUEUE_BYTES: usize = 1 << 20;
+
 thread_local! {
     static PROMISE_REJECTION: Cell<Option<String>> = Cell::new(None);
 }
@@ -42,9 +58,37 @@ struct InstanceState {
     handle: WorkerHandle,
     io_waiter: Option<IoWaiter>,
 
+    /// Specifier -> compiled module, populated by `Instance::load_module_graph`. Keeping this
+    /// on `InstanceState` (rather than a local variable) lets `module_resolve_callback`, which
+    /// is a bare `extern "C"` function with no closure environment, look modules up through the
+    /// isolate slot the same way every other callback in this file does.
+    modules: HashMap<String, v8::Global<v8::Module>>,
+
+    /// Backing `ArrayBuffer` for the shared queue, handed to JS once at init (see
+    /// `init_global_env`) and reused on every batched delivery in the drive-to-completion loop.
+    shared_queue_buf: Option<v8::Global<v8::ArrayBuffer>>,
+
+    /// Set for as long as we're inside a JS callback dispatched by native code: the
+    /// `_dispatchEvent`/`_dispatchAsyncBatch` calls in `Instance::run` (via `with_callback_guard`),
+    /// and op callbacks such as `call_service_callback` (same helper, applied directly there).
+    /// `IoWaiter::wait`/`drain_ready` is only safe to call from the top of the drive loop in
+    /// `run`; calling it again from within one of these callbacks would block the isolate thread
+    /// on itself. See `InstanceState::guard_against_reentrant_io`.
+    in_js_callback: bool,
+
     done: bool,
 
-    fetch_response_channel: Option<tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>>,
+    /// The result channel for whichever task is currently running, in the shape appropriate to
+    /// that task's completion semantics -- a fetch resolves with a `ResponseObject`, while
+    /// scheduled/alarm tasks only ever resolve with success or failure.
+    response_channel: Option<ResponseChannel>,
+}
+
+/// See `InstanceState::response_channel`.
+enum ResponseChannel {
+    Fetch(tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>),
+    Scheduled(tokio::sync::oneshot::Sender<ExecutionResult<()>>),
+    Alarm(tokio::sync::oneshot::Sender<ExecutionResult<()>>),
 }
 
 pub struct InstanceHandle {
@@ -64,18 +108,122 @@ enum Task {
         tokio::sync::oneshot::Sender<ExecutionResult<ResponseObject>>,
         IoScopeConsumer,
     ),
+    /// A cron-style scheduled trigger. Carries the scheduled UNIX timestamp (ms) and the cron
+    /// expression that fired it; dispatched to the worker's `scheduled` handler, if any.
+    Scheduled(
+        u64,
+        String,
+        tokio::sync::oneshot::Sender<ExecutionResult<()>>,
+        IoScopeConsumer,
+    ),
+    /// A deferred alarm, as set by the worker itself through a Durable-Object-style alarm API.
+    /// Carries the UNIX timestamp (ms) the alarm was scheduled for.
+    Alarm(
+        u64,
+        tokio::sync::oneshot::Sender<ExecutionResult<()>>,
+        IoScopeConsumer,
+    ),
 }
 
 struct DoubleMleGuard {
     triggered_mle: bool,
 }
 
+/// A thin writer over the shared `ArrayBuffer` handed to JS once at init (see
+/// `InstanceState::init_global_env`). Layout, modeled on deno_core's `SharedQueue`:
+///
+/// ```text
+/// [0..4)                                 record count (u32 LE)
+/// [4..4 + 4*MAX_RECORDS)                  per-record end offsets from the buffer start (u32 LE)
+/// [4 + 4*MAX_RECORDS..)                   packed records: raw JSON bytes, back to back
+/// ```
+///
+/// Note there is no length prefix *inside* the records region -- a record's start is the previous
+/// record's end offset (or the start of the region, for the first record), and its end is its own
+/// entry in the offset table. The JS-side reader needs both the offset table and the count, not
+/// just the raw bytes.
+///
+/// One `SharedQueue` is created fresh per drive-to-completion wakeup (see `Instance::run`) and
+/// `push` is called until either the batch is exhausted or the arena is full; the backing
+/// `ArrayBuffer` itself is long-lived and reused across wakeups.
+struct SharedQueue<'s> {
+    buf: v8::Local<'s, v8::ArrayBuffer>,
+    num_records: u32,
+    records_end: usize,
+}
+
+impl<'s> SharedQueue<'s> {
+    const HEADER_BYTES: usize = 4 + 4 * SHARED_QUEUE_MAX_RECORDS as usize;
+
+    fn new(scope: &mut v8::HandleScope<'s>, global_buf: &v8::Global<v8::ArrayBuffer>) -> Self {
+        let buf = v8::Local::new(scope, global_buf);
+        let mut queue = Self {
+            buf,
+            num_records: 0,
+            records_end: Self::HEADER_BYTES,
+        };
+        queue.write_u32(0, 0);
+        queue
+    }
+
+    fn backing_store(&mut self) -> &mut [u8] {
+        // Safety: the backing store outlives `self` (it's owned by the instance-wide shared
+        // `ArrayBuffer`), and taking `&mut self` here means Rust's borrow checker -- not just
+        // convention -- rules out two live aliases of the returned slice.
+        let store = self.buf.get_backing_store();
+        unsafe {
+            std::slice::from_raw_parts_mut(store.data().unwrap().as_ptr() as *mut u8, store.byte_length())
+        }
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.backing_store()[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Appends one raw JSON record; its boundaries are recorded in the header's offset table, not
+    /// in the record bytes themselves. Returns `false` (without writing anything) if the record
+    /// wouldn't fit, either because we've hit `SHARED_QUEUE_MAX_RECORDS` or because the arena
+    /// doesn't have enough bytes left — the caller is expected to fall back to delivering that
+    /// completion (and the rest of the batch) one at a time.
+    fn push(&mut self, data: &str) -> bool {
+        if self.num_records as usize >= SHARED_QUEUE_MAX_RECORDS {
+            return false;
+        }
+
+        let bytes = data.as_bytes();
+        let record_start = self.records_end;
+        let record_end = record_start + bytes.len();
+        if record_end > self.backing_store().len() {
+            return false;
+        }
+
+        self.backing_store()[record_start..record_end].copy_from_slice(bytes);
+        self.records_end = record_end;
+
+        let offset_slot = 4 + 4 * self.num_records as usize;
+        self.write_u32(offset_slot, record_end as u32);
+
+        self.num_records += 1;
+        self.write_u32(0, self.num_records);
+        true
+    }
+}
+
 impl Task {
     fn make_event(&self) -> ServiceEvent {
         match self {
             Task::Fetch(ref req, _, _) => ServiceEvent::Fetch(FetchEvent {
                 request: req.clone(),
             }),
+            Task::Scheduled(scheduled_time_ms, ref cron, _, _) => {
+                ServiceEvent::Scheduled(ScheduledEvent {
+                    scheduled_time_ms: *scheduled_time_ms,
+                    cron: cron.clone(),
+                })
+            }
+            Task::Alarm(scheduled_time_ms, _, _) => ServiceEvent::Alarm(AlarmEvent {
+                scheduled_time_ms: *scheduled_time_ms,
+            }),
         }
     }
 }
@@ -108,6 +256,43 @@ impl InstanceHandle {
             }
         }
     }
+
+    pub async fn schedule(&self, scheduled_time_ms: u64, cron: String) -> ExecutionResult<()> {
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let (_io_scope, io_scope_consumer) = IoScope::new();
+
+        // Send fails if the instance has terminated
+        self.task_tx
+            .send(Task::Scheduled(
+                scheduled_time_ms,
+                cron,
+                result_tx,
+                io_scope_consumer,
+            ))
+            .await
+            .map_err(|_| ExecutionError::NoSuchWorker)?;
+
+        match result_rx.await {
+            Ok(res) => res,
+            Err(_) => Err(ExecutionError::RuntimeThrowsException),
+        }
+    }
+
+    pub async fn alarm(&self, scheduled_time_ms: u64) -> ExecutionResult<()> {
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let (_io_scope, io_scope_consumer) = IoScope::new();
+
+        // Send fails if the instance has terminated
+        self.task_tx
+            .send(Task::Alarm(scheduled_time_ms, result_tx, io_scope_consumer))
+            .await
+            .map_err(|_| ExecutionError::NoSuchWorker)?;
+
+        match result_rx.await {
+            Ok(res) => res,
+            Err(_) => Err(ExecutionError::RuntimeThrowsException),
+        }
+    }
 }
 
 impl Drop for InstanceHandle {
@@ -179,8 +364,11 @@ impl Instance {
                 conf: Arc::new(conf.clone()),
                 handle: worker_handle,
                 io_waiter: None,
+                modules: HashMap::new(),
+                shared_queue_buf: None,
+                in_js_callback: false,
                 done: false,
-                fetch_response_channel: None,
+                response_channel: None,
             }),
         };
         Ok((instance, handle, time_control))
@@ -197,8 +385,164 @@ impl Instance {
         Ok(script)
     }
 
+    /// Compiles `source` as an ES module under `specifier`, without instantiating it.
+    fn compile_module<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        specifier: &str,
+        source: &str,
+    ) -> GenericResult<v8::Local<'s, v8::Module>> {
+        let source_text = v8::String::new(scope, source)
+            .ok_or_else(|| GenericError::ScriptInitException("module compilation failed".into()))?;
+        let name = v8::String::new(scope, specifier)
+            .ok_or_else(|| GenericError::ScriptInitException("module compilation failed".into()))?;
+        let origin = v8::ScriptOrigin::new(
+            scope,
+            name.into(),
+            0,
+            0,
+            false,
+            0,
+            None,
+            false,
+            false,
+            true, // is_module
+        );
+        let source = v8::script_compiler::Source::new(source_text, Some(&origin));
+        v8::script_compiler::compile_module(scope, source).ok_or_else(|| {
+            GenericError::ScriptInitException(format!(
+                "failed to compile module `{}`",
+                specifier
+            ))
+        })
+    }
+
+    /// Recursively fetches and compiles the full dependency graph reachable from
+    /// `entry_specifier`/`entry_source`, recording each module in `InstanceState::modules` keyed
+    /// by specifier (this both dedupes fetches and gives `module_resolve_callback` something to
+    /// resolve against later).
+    ///
+    /// This is the async phase of module loading: resolving a specifier to source text goes
+    /// through the same `IoWaiter`/`IoScope` path as `call_service_callback`, so a worker that
+    /// imports remote modules is still bound by the regular I/O budget/timeout. Instantiation
+    /// and evaluation (`instantiate_and_evaluate`) only start once every transitive dependency is
+    /// local, since `v8::Module::instantiate_module`'s resolve callback is synchronous.
+    fn load_module_graph<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        io_waiter: &mut IoWaiter,
+        entry_specifier: &str,
+        entry_source: &str,
+    ) -> GenericResult<v8::Local<'s, v8::Module>> {
+        let mut pending = vec![(entry_specifier.to_string(), entry_source.to_string())];
+
+        // Tracks every specifier that's either already compiled (in `InstanceState::modules`) or
+        // already sitting on `pending` waiting to be. Without this, a diamond dependency -- two
+        // modules that both import a third, common module, both processed before the common one
+        // is popped off the stack -- would have that common module fetched and pushed onto
+        // `pending` once per importer, since checking `modules` alone only catches a specifier
+        // once it has *finished* compiling.
+        let mut enqueued: HashSet<String> = HashSet::new();
+        enqueued.insert(entry_specifier.to_string());
+
+        while let Some((specifier, source)) = pending.pop() {
+            if InstanceState::get(scope).modules.contains_key(&specifier) {
+                continue;
+            }
+
+            let module = Self::compile_module(scope, &specifier, &source)?;
+
+            for i in 0..module.get_module_requests_length() {
+                let request = module.get_module_request(i);
+                let raw_specifier = request.get_specifier().to_rust_string_lossy(scope);
+                let dep_specifier = resolve_specifier(&specifier, &raw_specifier)?;
+
+                if InstanceState::get(scope).modules.contains_key(&dep_specifier)
+                    || !enqueued.insert(dep_specifier.clone())
+                {
+                    continue;
+                }
+
+                // Async phase: block on the module-loading IoWaiter, built from the same
+                // configuration as a task's, so remote module loads get the same I/O budget and
+                // timeout.
+                let dep_source = io_waiter.fetch_module(&dep_specifier).map_err(|e| {
+                    GenericError::ScriptInitException(format!(
+                        "failed to load module `{}`: {}",
+                        dep_specifier, e
+                    ))
+                })?;
+
+                pending.push((dep_specifier, dep_source));
+            }
+
+            let global_module = v8::Global::new(scope, module);
+            InstanceState::get(scope).modules.insert(specifier, global_module);
+        }
+
+        let entry = InstanceState::get(scope)
+            .modules
+            .get(entry_specifier)
+            .ok_or_else(|| GenericError::Other("module graph missing entry module".into()))?;
+        Ok(v8::Local::new(scope, entry))
+    }
+
+    /// Instantiates an already-fully-fetched module graph and evaluates its entry point.
+    /// Instantiation is synchronous and requires every transitive dependency to already be in
+    /// `InstanceState::modules`, which `load_module_graph` guarantees.
+    fn instantiate_and_evaluate<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        module: v8::Local<'s, v8::Module>,
+    ) -> GenericResult<()> {
+        if !module
+            .instantiate_module(scope, module_resolve_callback)
+            .unwrap_or(false)
+        {
+            return Err(GenericError::ScriptInitException(
+                "module instantiation failed".into(),
+            ));
+        }
+
+        let result = module
+            .evaluate(scope)
+            .ok_or_else(|| GenericError::ScriptInitException("module evaluation failed".into()))?;
+
+        // A rejected (or still-pending) top-level evaluation doesn't raise a catchable exception
+        // the way a thrown error does, so the caller's `try_catch.check_on_init()` right after
+        // this returns wouldn't otherwise see it. Feed the actual reason into the same
+        // `PROMISE_REJECTION` slot `on_promise_rejection` uses, so that shared, try_catch-based
+        // path is what turns it into a `GenericError` -- instead of this function inventing a
+        // second, less informative one of its own.
+        if let Ok(promise) = v8::Local::<v8::Promise>::try_from(result) {
+            match promise.state() {
+                v8::PromiseState::Rejected => {
+                    let reason = promise
+                        .result(scope)
+                        .to_string(scope)
+                        .map(|s| s.to_rust_string_lossy(scope))
+                        .unwrap_or_else(|| "(no message)".into());
+                    PROMISE_REJECTION.with(|x| {
+                        x.set(Some(format!("module evaluation rejected: {}", reason)))
+                    });
+                }
+                v8::PromiseState::Pending => {
+                    // Top-level await is not supported: nothing drives this promise to
+                    // completion past this point, so treating it as success would silently drop
+                    // whatever the module was waiting on.
+                    PROMISE_REJECTION.with(|x| {
+                        x.set(Some(
+                            "module evaluation did not complete synchronously (top-level await is not supported)"
+                                .into(),
+                        ))
+                    });
+                }
+                v8::PromiseState::Fulfilled => {}
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn run(mut self, ready_callback: impl FnOnce()) -> GenericResult<()> {
-        let state = self.state.take().unwrap();
+        let mut state = self.state.take().unwrap();
         let worker_runtime = state.worker_runtime.clone();
 
         // Init resources
@@ -217,12 +561,29 @@ impl Instance {
 
             // TODO: Compiler bombs?
             let librt = Self::compile(scope, LIBRT)?;
-            let script = Self::compile(scope, &state.script)?;
+
+            // Dedicated IoWaiter/IoScope for the module-loading phase, mirroring the per-task
+            // one set up in the main loop below, so remote module fetches share the regular I/O
+            // budget/timeout instead of running unbounded during init.
+            let (mut module_io_waiter, module_io_processor) =
+                IoWaiter::new(state.conf.clone(), state.worker_runtime.clone());
+            let (module_io_scope, module_io_scope_consumer) = IoScope::new();
+            state.rt.spawn(module_io_processor.run(module_io_scope_consumer));
+
+            let entry_source = state.script.clone();
+            scope.set_slot(state);
+
+            let module = Self::load_module_graph(
+                scope,
+                &mut module_io_waiter,
+                ENTRY_MODULE_SPECIFIER,
+                &entry_source,
+            )?;
+            drop(module_io_scope);
 
             // Notify that we are ready so that timing etc. can start
             ready_callback();
 
-            scope.set_slot(state);
             try_catch.check_on_init()?;
 
             librt.run(try_catch.as_mut());
@@ -231,7 +592,7 @@ impl Instance {
             // Now start the timer, since we are starting to run user code.
             InstanceState::get(try_catch).start_timer();
 
-            script.run(try_catch.as_mut());
+            Self::instantiate_and_evaluate(try_catch, module)?;
             try_catch.check_on_init()?;
         }
         info!("worker instance {} ready", worker_handle.id);
@@ -247,7 +608,9 @@ impl Instance {
             state.stop_timer();
             state.reset_timer();
 
-            // Cleanup state
+            // Cleanup state. Zero-copy request bodies are not cleared here: their backing
+            // stores are reference-counted and freed by `drop_body_backing_store` once V8
+            // actually releases them, which may be well after this task's slot in the loop.
             state.io_waiter = None; // drop it
             state.done = false;
 
@@ -265,7 +628,11 @@ impl Instance {
             // Start I/O processor (per-request).
             //
             // An `IoProcessor` receives the task's `IoScopeConsumer` as its argument, and stops when the
-            // corresponding `IoScope` is dropped.
+            // corresponding `IoScope` is dropped. `conf.executor.io_throttle_interval`, if set, is applied
+            // below around each `drain_ready` call: after the first completion is ready we hold off for
+            // one interval and take a non-blocking second look, so completions that land within that
+            // window get coalesced into the same batched dispatch instead of each paying its own
+            // native<->JS crossing.
             let (io_waiter, io_processor) =
                 IoWaiter::new(state.conf.clone(), state.worker_runtime.clone());
             state.rt.spawn(io_processor.run(io_scope));
@@ -278,7 +645,27 @@ impl Instance {
                 .map_err(|_| GenericError::Other("bad _dispatchEvent".into()))?;
             let recv = v8::undefined(scope);
             let event_js = native_to_js(scope, &event)?;
-            callback.call(scope, recv.into(), &[event_js]);
+
+            // Zero-copy body: swap in a real ArrayBuffer over the body's bytes instead of the
+            // base64 JSON string `native_to_js` would otherwise have produced, so a large body
+            // isn't copied a second time crossing the native<->JS boundary.
+            if let ServiceEvent::Fetch(ref fetch_event) = event {
+                if let Some(body) = fetch_event.request.body.as_ref() {
+                    let array_buffer = make_zero_copy_array_buffer(scope, Arc::from(body.as_slice()));
+                    let event_obj = v8::Local::<'_, v8::Object>::try_from(event_js)
+                        .map_err(|_| GenericError::Other("bad event object".into()))?;
+                    let request_key = make_string(scope, "request")?;
+                    let request_val = event_obj.get(scope, request_key.into()).check()?;
+                    let request_obj = v8::Local::<'_, v8::Object>::try_from(request_val)
+                        .map_err(|_| GenericError::Other("bad request object".into()))?;
+                    let body_key = make_string(scope, "body")?;
+                    request_obj.set(scope, body_key.into(), array_buffer.into());
+                }
+            }
+
+            with_callback_guard(scope, |scope| {
+                callback.call(scope, recv.into(), &[event_js]);
+            });
 
             // Drive to completion.
             loop {
@@ -286,12 +673,12 @@ impl Instance {
                     Ok(()) => {}
                     Err(e) => {
                         if e.terminates_worker() {
-                            InstanceState::try_send_fetch_response(try_catch, Err(e.clone()));
+                            InstanceState::try_fail_task(try_catch, e.clone());
                             return Err(GenericError::Execution(e));
                         } else {
                             debug!("non-critical exception: {:?}", e);
                             try_catch.reset();
-                            InstanceState::try_send_fetch_response(try_catch, Err(e));
+                            InstanceState::try_fail_task(try_catch, e);
                             break;
                         }
                     }
@@ -313,36 +700,108 @@ impl Instance {
                 // Renew lifetime
                 let state = InstanceState::get(scope);
 
-                let (callback, data) = match state.io_waiter.as_mut().unwrap().wait() {
+                // Drain every completion that's ready right now in one pass instead of paying a
+                // native<->JS crossing and a JSON parse per completion. `drain_ready` blocks for
+                // at least the first completion (same semantics the old `wait()` had) but then
+                // grabs everything else already available, up to `SHARED_QUEUE_MAX_RECORDS`.
+                if state.guard_against_reentrant_io().is_err() {
+                    InstanceState::try_fail_task(scope, ExecutionError::ReentrantIo);
+                    return Err(GenericError::Execution(ExecutionError::ReentrantIo));
+                }
+                let mut batch = match state
+                    .io_waiter
+                    .as_mut()
+                    .unwrap()
+                    .drain_ready(SHARED_QUEUE_MAX_RECORDS)
+                {
                     Some(x) => x,
                     None => {
                         // Doesn't necessarily need to terminate the instance but would need a lot of graceful
                         // handling on both the proxy side and the script side.
                         //
                         // So just terminate it now.
-                        InstanceState::try_send_fetch_response(
-                            scope,
-                            Err(ExecutionError::IoTimeout),
-                        );
+                        InstanceState::try_fail_task(scope, ExecutionError::IoTimeout);
                         return Err(GenericError::Execution(ExecutionError::IoTimeout));
                     }
                 };
+
+                // Cooperative throttling: hold off one interval and take a non-blocking second
+                // look, so completions that land within that window ride along in the same
+                // batch instead of each triggering its own wakeup and native<->JS crossing.
+                // Skipped once the first `drain_ready` already filled the batch -- there's
+                // nothing to coalesce into.
+                if batch.len() < SHARED_QUEUE_MAX_RECORDS {
+                    if let Some(interval) = state.conf.executor.io_throttle_interval {
+                        thread::sleep(interval);
+                        if let Some(more) = state
+                            .io_waiter
+                            .as_mut()
+                            .unwrap()
+                            .try_drain_ready(SHARED_QUEUE_MAX_RECORDS - batch.len())
+                        {
+                            batch.extend(more);
+                        }
+                    }
+                }
                 state.start_timer();
 
-                let callback = v8::Local::<'_, v8::Function>::new(scope, callback);
-                let json_text = v8::String::new(scope, data.as_str()).check()?;
-                let data = v8::json::parse(scope, json_text.into()).check()?;
-                callback.call(scope, recv.into(), &[data]);
+                let shared_queue_buf = state
+                    .shared_queue_buf
+                    .clone()
+                    .expect("shared queue not initialized");
+                let mut queue = SharedQueue::new(scope, &shared_queue_buf);
+
+                let mut batched_callbacks = Vec::with_capacity(batch.len());
+                let mut overflow = Vec::new();
+                // Once one record fails to fit, route everything from that point on to `overflow`
+                // too, even a later record that would itself still fit. Completions are in
+                // delivery order coming out of `drain_ready`; letting a smaller record "jump the
+                // queue" into the batched path would deliver it to JS before an earlier-ready
+                // completion that overflowed, reordering completions relative to when they
+                // actually became ready.
+                let mut overflowed = false;
+                for (callback, data) in batch {
+                    if !overflowed && queue.push(&data) {
+                        batched_callbacks.push(callback);
+                    } else {
+                        // Buffer full: fall back to the one-at-a-time path below for the rest so
+                        // nothing is dropped, and so order is preserved.
+                        overflowed = true;
+                        overflow.push((callback, data));
+                    }
+                }
+
+                if !batched_callbacks.is_empty() {
+                    let callbacks_array =
+                        v8::Array::new(scope, batched_callbacks.len() as i32);
+                    for (i, cb) in batched_callbacks.iter().enumerate() {
+                        let cb_local = v8::Local::new(scope, cb);
+                        callbacks_array.set_index(scope, i as u32, cb_local.into());
+                    }
+                    let count = v8::Integer::new(scope, batched_callbacks.len() as i32);
+
+                    let global = scope.get_current_context().global(scope);
+                    let dispatch_key = make_string(scope, "_dispatchAsyncBatch")?;
+                    let dispatch = global.get(scope, dispatch_key.into()).check()?;
+                    let dispatch = v8::Local::<'_, v8::Function>::try_from(dispatch)
+                        .map_err(|_| GenericError::Other("bad _dispatchAsyncBatch".into()))?;
+                    with_callback_guard(scope, |scope| {
+                        dispatch.call(scope, recv.into(), &[callbacks_array.into(), count.into()]);
+                    });
+                }
+
+                for (callback, data) in overflow {
+                    let callback = v8::Local::<'_, v8::Function>::new(scope, callback);
+                    let json_text = v8::String::new(scope, data.as_str()).check()?;
+                    let data = v8::json::parse(scope, json_text.into()).check()?;
+                    with_callback_guard(scope, |scope| {
+                        callback.call(scope, recv.into(), &[data]);
+                    });
+                }
             }
 
             // Script marked itself as done but we haven't got any response.
-            InstanceState::try_send_fetch_response(
-                try_catch,
-                Ok(ResponseObject {
-                    status: 500,
-                    ..Default::default()
-                }),
-            );
+            InstanceState::try_complete_task_with_default(try_catch);
         }
         Ok(())
     }
@@ -353,12 +812,31 @@ impl InstanceState {
         isolate.get_slot_mut::<Self>().unwrap()
     }
 
+    /// Guards against reentrant blocking I/O before handing out the `IoWaiter` -- this is the
+    /// only path by which op handlers like `call_service_callback` can reach it, so the check
+    /// has to live here rather than only at the top of the drive loop in `Instance::run` (which
+    /// by construction never runs with `in_js_callback` set, and so can never observe it trip).
     fn io_waiter(&mut self) -> JsResult<&mut IoWaiter> {
+        self.guard_against_reentrant_io()?;
         self.io_waiter.as_mut().ok_or_else(|| {
             JsError::new(JsErrorKind::Error, Some("io service not available".into()))
         })
     }
 
+    /// Refuses to proceed if we're currently inside a JS callback dispatched by native code (see
+    /// `in_js_callback`). Call this immediately before any blocking `IoWaiter` wait -- doing the
+    /// wait anyway would block the isolate thread waiting on a completion that can only be
+    /// delivered by itself, i.e. deadlock.
+    fn guard_against_reentrant_io(&self) -> JsResult<()> {
+        if self.in_js_callback {
+            return Err(JsError::new(
+                JsErrorKind::Error,
+                Some("attempted a blocking I/O wait from within a JS callback".into()),
+            ));
+        }
+        Ok(())
+    }
+
     fn start_timer(&self) {
         drop(self.timer_tx.send(TimerControl::Start));
     }
@@ -372,11 +850,16 @@ impl InstanceState {
     }
 
     /// Builds the global object.
-    fn init_global_env<'s>(&self, scope: &mut v8::HandleScope<'s>) -> GenericResult<()> {
+    fn init_global_env<'s>(&mut self, scope: &mut v8::HandleScope<'s>) -> GenericResult<()> {
         let global = scope.get_current_context().global(scope);
+
+        let shared_queue_ab = v8::ArrayBuffer::new(scope, SHARED_QUEUE_BYTES);
+        self.shared_queue_buf = Some(v8::Global::new(scope, shared_queue_ab));
+
         let global_props = btreemap! {
             "_callService".into() => make_function(scope, call_service_callback)?.into(),
             "global".into() => global.into(),
+            "_sharedQueue".into() => shared_queue_ab.into(),
         };
         add_props_to_object(scope, &global, global_props)?;
         Ok(())
@@ -385,20 +868,71 @@ impl InstanceState {
     fn populate_with_task(&mut self, task: Task) -> GenericResult<IoScopeConsumer> {
         match task {
             Task::Fetch(_, res, io_scope) => {
-                self.fetch_response_channel = Some(res);
+                self.response_channel = Some(ResponseChannel::Fetch(res));
+                Ok(io_scope)
+            }
+            Task::Scheduled(_, _, res, io_scope) => {
+                self.response_channel = Some(ResponseChannel::Scheduled(res));
+                Ok(io_scope)
+            }
+            Task::Alarm(_, res, io_scope) => {
+                self.response_channel = Some(ResponseChannel::Alarm(res));
                 Ok(io_scope)
             }
         }
     }
 
+    /// Sends `res` on the active channel if (and only if) the current task is a fetch. Returns
+    /// `false`, without consuming the channel, if the current task is scheduled/alarm instead --
+    /// use `try_send_done_response` for those.
     fn try_send_fetch_response(
         isolate: &mut v8::Isolate,
         res: ExecutionResult<ResponseObject>,
     ) -> bool {
-        if let Some(ch) = InstanceState::get(isolate).fetch_response_channel.take() {
-            ch.send(res).is_ok()
-        } else {
-            false
+        match InstanceState::get(isolate).response_channel.take() {
+            Some(ResponseChannel::Fetch(ch)) => ch.send(res).is_ok(),
+            other => {
+                InstanceState::get(isolate).response_channel = other;
+                false
+            }
+        }
+    }
+
+    /// Sends `res` on the active channel if (and only if) the current task is scheduled/alarm,
+    /// both of which complete with success/failure alone. The counterpart of
+    /// `try_send_fetch_response` for non-HTTP tasks.
+    fn try_send_done_response(isolate: &mut v8::Isolate, res: ExecutionResult<()>) -> bool {
+        match InstanceState::get(isolate).response_channel.take() {
+            Some(ResponseChannel::Scheduled(ch)) => ch.send(res).is_ok(),
+            Some(ResponseChannel::Alarm(ch)) => ch.send(res).is_ok(),
+            other => {
+                InstanceState::get(isolate).response_channel = other;
+                false
+            }
+        }
+    }
+
+    /// Fails whichever task is currently active, regardless of its kind.
+    fn try_fail_task(isolate: &mut v8::Isolate, err: ExecutionError) -> bool {
+        if InstanceState::try_send_fetch_response(isolate, Err(err.clone())) {
+            return true;
+        }
+        InstanceState::try_send_done_response(isolate, Err(err))
+    }
+
+    /// The script called `_callService(Sync(Done))` without otherwise reporting a result. A fetch
+    /// without an explicit `SendFetchResponse` is treated as a server error, matching prior
+    /// behavior; a scheduled/alarm task with no explicit result is a plain success.
+    fn try_complete_task_with_default(isolate: &mut v8::Isolate) {
+        let sent_fetch = InstanceState::try_send_fetch_response(
+            isolate,
+            Ok(ResponseObject {
+                status: 500,
+                ..Default::default()
+            }),
+        );
+        if !sent_fetch {
+            InstanceState::try_send_done_response(isolate, Ok(()));
         }
     }
 }
@@ -436,34 +970,174 @@ extern "C" fn on_promise_rejection(_msg: v8::PromiseRejectMessage<'_>) {
     PROMISE_REJECTION.with(|x| x.set(Some("unhandled promise rejection".into())));
 }
 
+/// Runs `f` with `InstanceState::in_js_callback` set, so that a reentrant blocking I/O wait
+/// triggered from within `f` (directly, or indirectly through a callback it invokes) is rejected
+/// by `InstanceState::guard_against_reentrant_io` instead of deadlocking the isolate thread.
+fn with_callback_guard<R>(
+    scope: &mut v8::HandleScope,
+    f: impl FnOnce(&mut v8::HandleScope) -> R,
+) -> R {
+    let prev = InstanceState::get(scope).in_js_callback;
+    InstanceState::get(scope).in_js_callback = true;
+    let result = f(scope);
+    InstanceState::get(scope).in_js_callback = prev;
+    result
+}
+
+/// Wraps `bytes` in a `v8::ArrayBuffer` using an external backing store, so they're exposed to JS
+/// without being copied. The `Arc`'s lifetime is driven by the backing store's deleter callback
+/// (`drop_body_backing_store`) rather than by the task loop: a worker script can stash
+/// `event.request.body` somewhere that outlives the task that created it (another promise, a
+/// module-level variable, ...), and in this multi-tenant runtime a fixed "clear at the start of
+/// the next task" schedule would free the bytes out from under that reference -- or worse, let
+/// the freed allocation be reused for a *different* client's subsequent request/response body.
+fn make_zero_copy_array_buffer<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    bytes: Arc<[u8]>,
+) -> v8::Local<'s, v8::ArrayBuffer> {
+    let len = bytes.len();
+    let data_ptr = bytes.as_ptr() as *mut c_void;
+
+    // Leaked into the backing store's `deleter_data`; reclaimed by `drop_body_backing_store`
+    // once V8 has actually released the backing store (and every ArrayBuffer/view sharing it).
+    let deleter_data = Box::into_raw(Box::new(bytes)) as *mut c_void;
+
+    let backing_store = unsafe {
+        v8::ArrayBuffer::new_backing_store_from_ptr(
+            data_ptr,
+            len,
+            drop_body_backing_store,
+            deleter_data,
+        )
+    }
+    .make_shared();
+
+    v8::ArrayBuffer::with_backing_store(scope, &backing_store)
+}
+
+extern "C" fn drop_body_backing_store(_data: *mut c_void, _len: usize, deleter_data: *mut c_void) {
+    // SAFETY: `deleter_data` is the `Box<Arc<[u8]>>` pointer leaked in
+    // `make_zero_copy_array_buffer`. V8 calls this exactly once, when the backing store is no
+    // longer referenced by any ArrayBuffer or view, so reclaiming it here is sound regardless of
+    // which task's loop iteration happens to be running at the time.
+    unsafe {
+        drop(Box::from_raw(deleter_data as *mut Arc<[u8]>));
+    }
+}
+
+/// Reads an `ArrayBufferView`'s bytes directly out of its backing store, without going through
+/// `v8::json::parse` — the zero-copy counterpart of the base64-in-JSON path `js_to_native` uses
+/// for every other field.
+fn read_array_buffer_view(view: v8::Local<v8::ArrayBufferView>) -> Vec<u8> {
+    let mut out = vec![0u8; view.byte_length()];
+    view.copy_contents(&mut out);
+    out
+}
+
+/// Resolves an import specifier against its referrer's specifier by joining it onto the
+/// referrer's directory, the same way a relative URL or filesystem path resolves. Module
+/// specifiers in rusty-workers are opaque strings rather than real files, but a multi-file
+/// worker's `./`/`../`-relative imports still need directory-relative resolution: a module at
+/// `lib/a.js` importing `./b.js` must resolve to `lib/b.js`, not `b.js`. A specifier that doesn't
+/// start with `.` is treated as already resolved (e.g. a bare or absolute specifier).
+///
+/// Errors if a `../` climbs above the root (the entry module's directory) -- there's nothing above which to
+/// resolve, so silently stopping the climb (which would quietly resolve to the wrong module,
+/// rather than to an obviously-broken one) is worse than failing the import outright.
+fn resolve_specifier(referrer: &str, specifier: &str) -> GenericResult<String> {
+    if !specifier.starts_with('.') {
+        return Ok(specifier.to_string());
+    }
+
+    let mut segments: Vec<&str> = match referrer.rfind('/') {
+        Some(idx) => referrer[..idx].split('/').collect(),
+        None => Vec::new(),
+    };
+
+    for part in specifier.split('/') {
+        match part {
+            "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(GenericError::ScriptInitException(format!(
+                        "import specifier `{}` climbs above the root when resolved against `{}`",
+                        specifier, referrer
+                    )));
+                }
+            }
+            other => segments.push(other),
+        }
+    }
+
+    Ok(segments.join("/"))
+}
+
+/// Resolve callback passed to `v8::Module::instantiate_module`. V8 requires a bare function
+/// pointer here (no closure environment), so it looks the calling module and its resolved
+/// dependency up through the isolate slot, the same way every other callback in this file reaches
+/// `InstanceState`.
+fn module_resolve_callback<'s>(
+    context: v8::Local<'s, v8::Context>,
+    specifier: v8::Local<'s, v8::String>,
+    _import_assertions: v8::Local<'s, v8::FixedArray>,
+    referrer: v8::Local<'s, v8::Module>,
+) -> Option<v8::Local<'s, v8::Module>> {
+    let scope = &mut unsafe { v8::CallbackScope::new(context) };
+    let specifier = specifier.to_rust_string_lossy(scope);
+
+    let state = InstanceState::get(scope);
+    let referrer_specifier = state
+        .modules
+        .iter()
+        .find(|(_, m)| v8::Local::new(scope, (*m).clone()) == referrer)
+        .map(|(s, _)| s.clone())?;
+
+    let resolved = resolve_specifier(&referrer_specifier, &specifier).ok()?;
+    let module = InstanceState::get(scope).modules.get(&resolved)?;
+    Some(v8::Local::new(scope, module))
+}
+
+// This services an op invoked synchronously from JS, which may in turn block on `IoWaiter`
+// (the `ServiceCall::Async` arm below). `with_callback_guard` is applied directly here rather
+// than assumed from `wrap_callback` (engine.rs, untouched by this change) so the reentrancy
+// guard's coverage of op-invoked code doesn't depend on behavior this commit can't see or test.
 fn call_service_callback(
     scope: &mut v8::HandleScope,
     args: v8::FunctionCallbackArguments,
     mut _retval: v8::ReturnValue,
 ) {
     wrap_callback(scope, |scope| {
-        let scope = &mut v8::HandleScope::new(scope);
-        let call: ServiceCall = js_to_native(scope, args.get(0))?;
-        match call {
-            ServiceCall::Sync(call) => match call {
-                SyncCall::Log(s) => {
-                    debug!("log: {}", s);
-                }
-                SyncCall::Done => {
+        with_callback_guard(scope, |scope| {
+            let scope = &mut v8::HandleScope::new(scope);
+            let call: ServiceCall = js_to_native(scope, args.get(0))?;
+            match call {
+                ServiceCall::Sync(call) => match call {
+                    SyncCall::Log(s) => {
+                        debug!("log: {}", s);
+                    }
+                    SyncCall::Done => {
+                        let state = InstanceState::get(scope);
+                        state.done = true;
+                    }
+                    SyncCall::SendFetchResponse(mut res) => {
+                        // Zero-copy path: the body may be passed as an ArrayBufferView in the second
+                        // argument instead of being embedded as base64 inside `res.body`, so a large
+                        // response body isn't JSON-round-tripped. Read its bytes straight out of the
+                        // view's backing store.
+                        if let Ok(view) = v8::Local::<'_, v8::ArrayBufferView>::try_from(args.get(1)) {
+                            res.body = Some(read_array_buffer_view(view));
+                        }
+                        InstanceState::try_send_fetch_response(scope, Ok(res));
+                    }
+                },
+                ServiceCall::Async(call) => {
+                    let callback = v8::Local::<'_, v8::Function>::try_from(args.get(1))?;
+                    let callback = v8::Global::new(scope, callback);
                     let state = InstanceState::get(scope);
-                    state.done = true;
+                    state.io_waiter()?.issue(false, call, callback)?;
                 }
-                SyncCall::SendFetchResponse(res) => {
-                    InstanceState::try_send_fetch_response(scope, Ok(res));
-                }
-            },
-            ServiceCall::Async(call) => {
-                let callback = v8::Local::<'_, v8::Function>::try_from(args.get(1))?;
-                let callback = v8::Global::new(scope, callback);
-                let state = InstanceState::get(scope);
-                state.io_waiter()?.issue(false, call, callback)?;
             }
-        }
-        Ok(())
+            Ok(())
+        })
     })
 }